@@ -0,0 +1,266 @@
+use super::*;
+use clap::{App, Arg};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// The wormhole control server to connect to, by default.
+pub const DEFAULT_CONTROL_HOST: &str = "wormhole.cloud";
+
+/// How `local::setup_new_stream` picks a destination for a new stream.
+#[derive(Debug, Clone)]
+pub enum ForwardMode {
+    /// Forward every stream to the same local port. The default, and the
+    /// only mode that benefits from `Config::local_pool_size`.
+    FixedPort { port: u16 },
+    /// Speak SOCKS5 to the browser over the tunnel and dial whatever
+    /// destination it asks for, optionally chaining through an upstream
+    /// SOCKS5 proxy instead of dialing the target directly.
+    Socks5 { proxy_addr: Option<SocketAddr> },
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub control_url: String,
+    pub local_port: u16,
+    pub forward_mode: ForwardMode,
+    pub client_id: Option<ClientId>,
+    pub secret_key: Option<String>,
+    pub sub_domain: String,
+    /// Number of idle connections to `local_port` to keep dialed ahead of
+    /// time so a new stream doesn't pay handshake latency. `0` disables
+    /// the pool and falls back to dialing on demand.
+    pub local_pool_size: usize,
+    /// How long a pooled connection can sit idle before it's reaped.
+    pub local_pool_idle_timeout: Duration,
+    /// PEM bundle of root CAs to trust instead of the built-in webpki roots,
+    /// for talking to a wormhole server behind a self-signed certificate.
+    pub root_ca_path: Option<PathBuf>,
+    /// Skip certificate validation entirely. Dangerous; only meant for
+    /// local testing against a dev server.
+    pub danger_accept_invalid_certs: bool,
+    /// PEM client certificate + private key presented for mutual TLS.
+    pub client_cert: Option<(PathBuf, PathBuf)>,
+    /// Base delay for the full-jitter exponential backoff between reconnect
+    /// attempts.
+    pub reconnect_base_delay: Duration,
+    /// Upper bound on the backoff delay between reconnect attempts.
+    pub reconnect_max_delay: Duration,
+    /// Give up after this many consecutive failed reconnect attempts.
+    /// `None` retries forever.
+    pub reconnect_max_attempts: Option<u32>,
+    /// A connection that stays up at least this long resets the backoff
+    /// attempt counter back to zero.
+    pub reconnect_reset_threshold: Duration,
+    /// How long to go without hearing anything from the wormhole server
+    /// before the watchdog assumes the tunnel is dead and forces a
+    /// reconnect. Defaults to `3 * PING_INTERVAL`.
+    pub missed_ping_timeout: Duration,
+    /// Bind address for the opt-in local request-inspection dashboard.
+    /// `None` (the default) leaves it disabled.
+    pub introspect_addr: Option<SocketAddr>,
+}
+
+impl Config {
+    /// Parse the CLI arguments (and environment) into a `Config`. Malformed
+    /// arguments make clap print usage and exit; a value that fails to parse
+    /// (port, pool size, address, ...) logs an error and returns `Err`.
+    pub fn get() -> Result<Config, ()> {
+        let matches = App::new("wormhole")
+            .version(env!("CARGO_PKG_VERSION"))
+            .author("MistShi")
+            .about("Expose your local web server to the internet")
+            .arg(
+                Arg::with_name("port")
+                    .short("p")
+                    .long("port")
+                    .takes_value(true)
+                    .required_unless("socks5")
+                    .help("The local port to forward traffic to"),
+            )
+            .arg(
+                Arg::with_name("subdomain")
+                    .long("subdomain")
+                    .short("s")
+                    .takes_value(true)
+                    .help("The sub-domain to request from the wormhole server"),
+            )
+            .arg(
+                Arg::with_name("host")
+                    .long("host")
+                    .takes_value(true)
+                    .help("Alternative wormhole server to connect to"),
+            )
+            .arg(
+                Arg::with_name("key")
+                    .long("key")
+                    .short("k")
+                    .takes_value(true)
+                    .env("WORMHOLE_KEY")
+                    .help("Secret key used to authenticate with the wormhole server"),
+            )
+            .arg(
+                Arg::with_name("pool-size")
+                    .long("pool-size")
+                    .takes_value(true)
+                    .default_value("0")
+                    .help("Number of idle connections to pre-warm to the local service"),
+            )
+            .arg(
+                Arg::with_name("root-ca")
+                    .long("root-ca")
+                    .takes_value(true)
+                    .help("PEM bundle of root CAs to trust (instead of the built-in roots) when connecting to the control server"),
+            )
+            .arg(
+                Arg::with_name("danger-accept-invalid-certs")
+                    .long("danger-accept-invalid-certs")
+                    .takes_value(false)
+                    .help("Disable TLS certificate validation entirely (unsafe, testing only)"),
+            )
+            .arg(
+                Arg::with_name("client-cert")
+                    .long("client-cert")
+                    .takes_value(true)
+                    .requires("client-key")
+                    .help("PEM client certificate to present for mutual TLS"),
+            )
+            .arg(
+                Arg::with_name("client-key")
+                    .long("client-key")
+                    .takes_value(true)
+                    .requires("client-cert")
+                    .help("PEM private key matching --client-cert"),
+            )
+            .arg(
+                Arg::with_name("reconnect-max-attempts")
+                    .long("reconnect-max-attempts")
+                    .takes_value(true)
+                    .help("Give up after this many consecutive failed reconnect attempts (default: retry forever)"),
+            )
+            .arg(
+                Arg::with_name("inspect")
+                    .long("inspect")
+                    .takes_value(true)
+                    .min_values(0)
+                    .max_values(1)
+                    .help("Serve a local request-inspection dashboard, e.g. `--inspect 127.0.0.1:4040`"),
+            )
+            .arg(
+                Arg::with_name("socks5")
+                    .long("socks5")
+                    .takes_value(false)
+                    .conflicts_with("port")
+                    .help("Forward each stream as a SOCKS5 connection instead of to a fixed local port"),
+            )
+            .arg(
+                Arg::with_name("socks5-proxy")
+                    .long("socks5-proxy")
+                    .takes_value(true)
+                    .requires("socks5")
+                    .help("Chain through this upstream SOCKS5 proxy instead of dialing targets directly"),
+            )
+            .get_matches();
+
+        let local_port: u16 = match matches.value_of("port") {
+            Some(port) => match port.parse() {
+                Ok(port) => port,
+                Err(_) => {
+                    error!("please specify a valid port to forward, e.g. `-p 8000`");
+                    return Err(());
+                }
+            },
+            // unused when --socks5 is set; setup_new_stream dials whatever
+            // target the browser's SOCKS5 request carries instead.
+            None => 0,
+        };
+
+        let forward_mode = if matches.is_present("socks5") {
+            let proxy_addr = match matches.value_of("socks5-proxy") {
+                Some(addr) => match addr.parse() {
+                    Ok(addr) => Some(addr),
+                    Err(_) => {
+                        error!("please specify a valid address for --socks5-proxy, e.g. `127.0.0.1:1080`");
+                        return Err(());
+                    }
+                },
+                None => None,
+            };
+            ForwardMode::Socks5 { proxy_addr }
+        } else {
+            ForwardMode::FixedPort { port: local_port }
+        };
+
+        let host = matches
+            .value_of("host")
+            .unwrap_or(DEFAULT_CONTROL_HOST)
+            .to_string();
+        let sub_domain = matches.value_of("subdomain").unwrap_or("").to_string();
+
+        let local_pool_size: usize = match matches.value_of("pool-size").unwrap().parse() {
+            Ok(size) => size,
+            Err(_) => {
+                error!("please specify a valid pool size, e.g. `--pool-size 4`");
+                return Err(());
+            }
+        };
+
+        let client_cert = match (matches.value_of("client-cert"), matches.value_of("client-key")) {
+            (Some(cert), Some(key)) => Some((PathBuf::from(cert), PathBuf::from(key))),
+            _ => None,
+        };
+
+        let reconnect_max_attempts = match matches.value_of("reconnect-max-attempts") {
+            Some(s) => match s.parse() {
+                Ok(n) => Some(n),
+                Err(_) => {
+                    error!("please specify a valid number for --reconnect-max-attempts");
+                    return Err(());
+                }
+            },
+            None => None,
+        };
+
+        let introspect_addr = match matches.value_of("inspect") {
+            Some(addr) => match addr.parse() {
+                Ok(addr) => Some(addr),
+                Err(_) => {
+                    error!("please specify a valid address for --inspect, e.g. `127.0.0.1:4040`");
+                    return Err(());
+                }
+            },
+            None => {
+                if matches.is_present("inspect") {
+                    Some(([127, 0, 0, 1], 4040).into())
+                } else {
+                    None
+                }
+            }
+        };
+
+        Ok(Config {
+            control_url: format!("wss://{}/wormhole", host),
+            local_port,
+            forward_mode,
+            client_id: None,
+            secret_key: matches.value_of("key").map(|s| s.to_string()),
+            sub_domain,
+            local_pool_size,
+            local_pool_idle_timeout: Duration::from_secs(60),
+            root_ca_path: matches.value_of("root-ca").map(PathBuf::from),
+            danger_accept_invalid_certs: matches.is_present("danger-accept-invalid-certs"),
+            client_cert,
+            reconnect_base_delay: Duration::from_millis(500),
+            reconnect_max_delay: Duration::from_secs(30),
+            reconnect_max_attempts,
+            reconnect_reset_threshold: Duration::from_secs(60),
+            missed_ping_timeout: Duration::from_secs(3 * PING_INTERVAL),
+            introspect_addr,
+        })
+    }
+
+    /// The public URL the tunnel is reachable at once activated.
+    pub fn activation_url(&self, sub_domain: &str) -> String {
+        format!("https://{}.{}", sub_domain, DEFAULT_CONTROL_HOST)
+    }
+}
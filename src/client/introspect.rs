@@ -0,0 +1,222 @@
+//! Opt-in local dashboard that shows what's flowing through the tunnel in
+//! real time, similar to ngrok's inspect UI. Disabled unless
+//! `Config::introspect_addr` is set.
+use super::*;
+use serde::Serialize;
+use std::collections::VecDeque;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+
+/// How many recent events to keep around for the dashboard and the
+/// by-id lookup endpoint.
+const RING_BUFFER_CAPACITY: usize = 200;
+
+const DASHBOARD_HTML: &str = include_str!("dashboard.html");
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HttpSummary {
+    pub method: Option<String>,
+    pub path: Option<String>,
+    pub status: Option<u16>,
+    pub headers: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CapturedEvent {
+    pub id: u64,
+    pub stream_id: String,
+    pub timestamp_ms: u128,
+    pub byte_count: usize,
+    pub http: Option<HttpSummary>,
+}
+
+struct Inspector {
+    events: VecDeque<CapturedEvent>,
+    next_id: u64,
+    clients: Vec<UnboundedSender<Message>>,
+}
+
+lazy_static::lazy_static! {
+    static ref INSPECTOR: Mutex<Inspector> = Mutex::new(Inspector {
+        events: VecDeque::with_capacity(RING_BUFFER_CAPACITY),
+        next_id: 0,
+        clients: Vec::new(),
+    });
+    static ref STARTED_AT: Instant = Instant::now();
+}
+
+/// Capture a frame as it flows through the tunnel: record it in the ring
+/// buffer and fan it out to any connected dashboard clients. Best-effort
+/// parses the payload as an HTTP message or request line.
+pub fn capture(stream_id: &StreamId, data: &[u8]) {
+    let mut inspector = INSPECTOR.lock().unwrap();
+    let id = inspector.next_id;
+    inspector.next_id += 1;
+
+    let event = CapturedEvent {
+        id,
+        stream_id: stream_id.to_string(),
+        timestamp_ms: STARTED_AT.elapsed().as_millis(),
+        byte_count: data.len(),
+        http: parse_http(data),
+    };
+
+    if inspector.events.len() >= RING_BUFFER_CAPACITY {
+        inspector.events.pop_front();
+    }
+    inspector.events.push_back(event.clone());
+
+    let payload = match serde_json::to_string(&event) {
+        Ok(json) => json,
+        Err(_) => return,
+    };
+    inspector
+        .clients
+        .retain(|tx| tx.unbounded_send(Message::text(payload.clone())).is_ok());
+}
+
+/// Look up a previously captured event by id, for the replay/debug endpoint.
+pub fn get_event(id: u64) -> Option<CapturedEvent> {
+    INSPECTOR.lock().unwrap().events.iter().find(|e| e.id == id).cloned()
+}
+
+fn parse_http(data: &[u8]) -> Option<HttpSummary> {
+    let text = std::str::from_utf8(data).ok()?;
+    let mut lines = text.split("\r\n");
+    let request_line = lines.next()?;
+    let mut parts = request_line.split_whitespace();
+    let first = parts.next()?;
+    let second = parts.next()?;
+    let third = parts.next();
+
+    let (method, path, status) = if first.starts_with("HTTP/") {
+        (None, None, second.parse().ok())
+    } else if third.map(|v| v.starts_with("HTTP/")).unwrap_or(false) {
+        (Some(first.to_string()), Some(second.to_string()), None)
+    } else {
+        return None;
+    };
+
+    let headers = lines
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(": "))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+    Some(HttpSummary { method, path, status, headers })
+}
+
+/// Start the dashboard server if `config.introspect_addr` is set. A no-op
+/// otherwise.
+pub async fn maybe_spawn_dashboard(config: &Config) {
+    let addr = match config.introspect_addr {
+        Some(addr) => addr,
+        None => return,
+    };
+
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("failed to bind introspection dashboard on {}: {:?}", addr, e);
+            return;
+        }
+    };
+
+    info!("introspection dashboard listening on http://{}", addr);
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle_connection(stream));
+                }
+                Err(e) => {
+                    warn!("introspection dashboard accept error: {:?}", e);
+                    return;
+                }
+            }
+        }
+    });
+}
+
+async fn handle_connection(mut stream: TcpStream) {
+    let mut peek_buf = [0u8; 1024];
+    let n = match stream.peek(&mut peek_buf).await {
+        Ok(n) => n,
+        Err(_) => return,
+    };
+    let head = String::from_utf8_lossy(&peek_buf[..n]).into_owned();
+
+    let is_websocket_upgrade = head.to_ascii_lowercase().contains("upgrade: websocket");
+    if head.starts_with("GET /ws") && is_websocket_upgrade {
+        handle_websocket(stream).await;
+    } else {
+        handle_http(&mut stream, &head).await;
+    }
+}
+
+async fn handle_websocket(stream: TcpStream) {
+    let ws_stream = match tokio_tungstenite::accept_async(stream).await {
+        Ok(ws) => ws,
+        Err(e) => {
+            warn!("introspection dashboard websocket handshake failed: {:?}", e);
+            return;
+        }
+    };
+
+    let (mut sink, mut source) = ws_stream.split();
+    let (tx, mut rx) = unbounded();
+    INSPECTOR.lock().unwrap().clients.push(tx);
+
+    // the dashboard doesn't send us anything meaningful, but we still need
+    // to drain its half of the socket so pings/closes are acknowledged
+    tokio::spawn(async move { while source.next().await.is_some() {} });
+
+    while let Some(message) = rx.next().await {
+        if sink.send(message).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn handle_http(stream: &mut TcpStream, head: &str) {
+    let path = head
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .unwrap_or("/");
+
+    let response = if path == "/" || path == "/index.html" {
+        http_response("200 OK", "text/html", DASHBOARD_HTML)
+    } else if let Some(id) = path.strip_prefix("/api/events/").and_then(|s| s.parse::<u64>().ok()) {
+        match get_event(id) {
+            Some(event) => http_response(
+                "200 OK",
+                "application/json",
+                &serde_json::to_string(&event).unwrap_or_default(),
+            ),
+            None => http_response("404 Not Found", "text/plain", "no such event"),
+        }
+    } else if path == "/api/events" {
+        let events: Vec<_> = INSPECTOR.lock().unwrap().events.iter().cloned().collect();
+        http_response(
+            "200 OK",
+            "application/json",
+            &serde_json::to_string(&events).unwrap_or_default(),
+        )
+    } else {
+        http_response("404 Not Found", "text/plain", "not found")
+    };
+
+    let _ = stream.write_all(response.as_bytes()).await;
+}
+
+fn http_response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
@@ -0,0 +1,509 @@
+use super::*;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Set up a new `StreamId` according to `config.forward_mode`: either a
+/// connection to a fixed local port, or a SOCKS5-forwarded connection to
+/// whatever destination the browser asks for over the tunnel. `data` is the
+/// payload of the `Data` frame that triggered this stream's creation --
+/// since the caller hasn't forwarded it anywhere yet (that's our job), we
+/// seed the stream's channel with it up front rather than making the
+/// handshake `.await` on a send that the caller can only make after we
+/// return, which would deadlock the whole control connection.
+pub async fn setup_new_stream(
+    config: &Config,
+    tunnel_tx: UnboundedSender<ControlPacket>,
+    stream_id: StreamId,
+    data: Vec<u8>,
+) {
+    match &config.forward_mode {
+        ForwardMode::FixedPort { port } => {
+            setup_fixed_port_stream(config, *port, tunnel_tx, stream_id, data).await
+        }
+        ForwardMode::Socks5 { proxy_addr } => {
+            let stream_rx = register_stream(&stream_id, data);
+            // the CONNECT request arrives in a later `Data` frame that only
+            // the caller's read loop can deliver, so the handshake runs in
+            // its own task instead of blocking that loop
+            let proxy_addr = *proxy_addr;
+            let config = config.clone();
+            tokio::spawn(async move {
+                setup_socks5_stream(&config, proxy_addr, tunnel_tx, stream_rx, stream_id).await
+            });
+        }
+    }
+}
+
+/// Open a TCP connection to the local service and wire it up to the tunnel
+/// so that `StreamId` becomes a full duplex pipe between the wormhole server
+/// and `localhost:port`. Pulls from `LOCAL_POOL` when a pre-warmed
+/// connection is available instead of paying the handshake cost inline.
+async fn setup_fixed_port_stream(
+    config: &Config,
+    port: u16,
+    mut tunnel_tx: UnboundedSender<ControlPacket>,
+    stream_id: StreamId,
+    data: Vec<u8>,
+) {
+    info!("stream[{:?}] -> setting up local tcp stream", stream_id.to_string());
+
+    let pooled = LOCAL_POOL.lock().unwrap().pop_front();
+    let local_tcp = match pooled {
+        Some(conn) => {
+            debug!("stream[{:?}] -> reusing pooled connection", stream_id.to_string());
+            conn.stream
+        }
+        None => match TcpStream::connect(("localhost", port)).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("failed to connect to local service: {:?}", e);
+                let _ = tunnel_tx.send(ControlPacket::Refused(stream_id)).await;
+                return;
+            }
+        },
+    };
+
+    let stream_rx = register_stream(&stream_id, data);
+    relay(config, stream_id, tunnel_tx, local_tcp, stream_rx).await;
+}
+
+/// Register `stream_id` in `ACTIVE_STREAMS` and return the receiving end of
+/// its channel, ready to be piped to a local TCP connection. `initial` is
+/// buffered into the channel before the sender is published, so a consumer
+/// reading from the returned receiver sees it immediately without anyone
+/// else needing to send on the channel first.
+fn register_stream(stream_id: &StreamId, initial: Vec<u8>) -> futures::channel::mpsc::UnboundedReceiver<StreamMessage> {
+    let (stream_tx, stream_rx) = unbounded::<StreamMessage>();
+    if !initial.is_empty() {
+        let _ = stream_tx.unbounded_send(StreamMessage::Data(initial));
+    }
+    ACTIVE_STREAMS.write().unwrap().insert(stream_id.clone(), stream_tx);
+    stream_rx
+}
+
+/// Pipe `local_tcp` <-> the tunnel for the lifetime of `stream_id`, spawning
+/// the two forwarding directions as independent tasks.
+async fn relay(
+    config: &Config,
+    stream_id: StreamId,
+    mut tunnel_tx: UnboundedSender<ControlPacket>,
+    local_tcp: TcpStream,
+    mut stream_rx: futures::channel::mpsc::UnboundedReceiver<StreamMessage>,
+) {
+    let (mut local_read, mut local_write) = local_tcp.into_split();
+
+    // local tcp -> tunnel
+    let tunnel_stream_id = stream_id.clone();
+    let mut tunnel_tx_read = tunnel_tx.clone();
+    let capture_traffic = config.introspect_addr.is_some();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 4096];
+        loop {
+            match local_read.read(&mut buf).await {
+                Ok(0) => {
+                    let _ = tunnel_tx_read.send(ControlPacket::End(tunnel_stream_id)).await;
+                    return;
+                }
+                Ok(n) => {
+                    if capture_traffic {
+                        introspect::capture(&tunnel_stream_id, &buf[..n]);
+                    }
+
+                    if tunnel_tx_read
+                        .send(ControlPacket::Data(tunnel_stream_id.clone(), buf[..n].to_vec()))
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    warn!("failed to read from local service: {:?}", e);
+                    let _ = tunnel_tx_read.send(ControlPacket::End(tunnel_stream_id)).await;
+                    return;
+                }
+            }
+        }
+    });
+
+    // tunnel -> local tcp
+    tokio::spawn(async move {
+        loop {
+            match stream_rx.next().await {
+                Some(StreamMessage::Data(data)) => {
+                    if let Err(e) = local_write.write_all(&data).await {
+                        warn!("failed to write to local service: {:?}", e);
+                        return;
+                    }
+                }
+                Some(StreamMessage::Close) | None => {
+                    let _ = local_write.shutdown().await;
+                    return;
+                }
+            }
+        }
+    });
+
+    let _ = tunnel_tx.send(ControlPacket::Init(stream_id)).await;
+}
+
+/// Background task that keeps `LOCAL_POOL` topped up to `config.local_pool_size`
+/// idle connections and reaps any that have sat around longer than
+/// `config.local_pool_idle_timeout`. Spawned once in `main` for the
+/// lifetime of the process, independent of individual tunnel reconnects.
+/// Only meaningful in `ForwardMode::FixedPort`.
+pub async fn maintain_pool(config: Config) {
+    let port = match config.forward_mode {
+        ForwardMode::FixedPort { port } => port,
+        ForwardMode::Socks5 { .. } => return,
+    };
+
+    loop {
+        {
+            let mut pool = LOCAL_POOL.lock().unwrap();
+            pool.retain(|conn| conn.idle_since.elapsed() < config.local_pool_idle_timeout);
+        }
+
+        let deficit = config.local_pool_size.saturating_sub(LOCAL_POOL.lock().unwrap().len());
+        for _ in 0..deficit {
+            match TcpStream::connect(("localhost", port)).await {
+                Ok(stream) => LOCAL_POOL.lock().unwrap().push_back(PooledConnection {
+                    stream,
+                    idle_since: Instant::now(),
+                }),
+                Err(e) => {
+                    warn!("failed to pre-warm local connection: {:?}", e);
+                    break;
+                }
+            }
+        }
+
+        tokio::time::delay_for(Duration::from_secs(1)).await;
+    }
+}
+
+const SOCKS5_VERSION: u8 = 0x05;
+const SOCKS5_METHOD_NO_AUTH: u8 = 0x00;
+const SOCKS5_CMD_CONNECT: u8 = 0x01;
+const SOCKS5_ATYP_IPV4: u8 = 0x01;
+const SOCKS5_ATYP_DOMAIN: u8 = 0x03;
+const SOCKS5_ATYP_IPV6: u8 = 0x04;
+const SOCKS5_REPLY_SUCCESS: u8 = 0x00;
+const SOCKS5_REPLY_GENERAL_FAILURE: u8 = 0x01;
+const SOCKS5_REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+
+/// Speak the server side of SOCKS5 over the tunnel: read the client's
+/// greeting and CONNECT request out of the stream's `Data` frames, dial the
+/// requested destination (optionally chained through `proxy_addr`), reply
+/// with the result, then fall back to plain byte relaying.
+async fn setup_socks5_stream(
+    config: &Config,
+    proxy_addr: Option<std::net::SocketAddr>,
+    mut tunnel_tx: UnboundedSender<ControlPacket>,
+    mut stream_rx: futures::channel::mpsc::UnboundedReceiver<StreamMessage>,
+    stream_id: StreamId,
+) {
+    info!("stream[{:?}] -> setting up socks5 stream", stream_id.to_string());
+
+    match socks5_handshake(&mut stream_rx, &mut tunnel_tx, &stream_id, proxy_addr).await {
+        Some((local_tcp, leftover)) => {
+            // anything left over is the first bytes of the proxied
+            // connection and needs to go out before we start relaying
+            // normally
+            if !leftover.is_empty() {
+                stream_rx = register_stream(&stream_id, leftover);
+            }
+
+            relay(config, stream_id, tunnel_tx, local_tcp, stream_rx).await;
+        }
+        None => {
+            // a failed CONNECT has already been answered with a SOCKS5
+            // error reply (a stream that closed mid-handshake gets none),
+            // so just make sure we don't leave a stream registered with no
+            // receiver behind it
+            ACTIVE_STREAMS.write().unwrap().remove(&stream_id);
+        }
+    }
+}
+
+/// Run the SOCKS5 greeting/CONNECT handshake against whatever the browser
+/// sends over `stream_rx`, replying over `tunnel_tx` as we go. Returns the
+/// dialed connection plus any bytes read past the handshake, or `None` if
+/// the handshake failed (the caller is responsible for forgetting the
+/// stream in that case, not this function).
+async fn socks5_handshake(
+    stream_rx: &mut futures::channel::mpsc::UnboundedReceiver<StreamMessage>,
+    tunnel_tx: &mut UnboundedSender<ControlPacket>,
+    stream_id: &StreamId,
+    proxy_addr: Option<std::net::SocketAddr>,
+) -> Option<(TcpStream, Vec<u8>)> {
+    let mut buf: Vec<u8> = Vec::new();
+
+    // greeting: VER NMETHODS METHODS...
+    read_until(stream_rx, &mut buf, |b| b.len() >= 2 && b.len() >= 2 + b[1] as usize).await?;
+    buf.drain(..2 + buf[1] as usize);
+
+    let _ = tunnel_tx
+        .send(ControlPacket::Data(stream_id.clone(), vec![SOCKS5_VERSION, SOCKS5_METHOD_NO_AUTH]))
+        .await;
+
+    // request: VER CMD RSV ATYP DST.ADDR DST.PORT
+    read_until(stream_rx, &mut buf, |b| socks5_request_len(b).is_some()).await?;
+    let request_len = socks5_request_len(&buf).expect("checked by read_until");
+    let target = match parse_socks5_request(&buf[..request_len]) {
+        Some(target) => target,
+        None => {
+            send_socks5_reply(tunnel_tx, stream_id, SOCKS5_REPLY_COMMAND_NOT_SUPPORTED).await;
+            return None;
+        }
+    };
+    buf.drain(..request_len);
+
+    info!("stream[{:?}] -> socks5 connect to {}", stream_id.to_string(), target);
+
+    let local_tcp = match dial_socks5_target(proxy_addr, &target).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            error!("failed to connect to socks5 target {}: {:?}", target, e);
+            send_socks5_reply(tunnel_tx, stream_id, SOCKS5_REPLY_GENERAL_FAILURE).await;
+            return None;
+        }
+    };
+
+    send_socks5_reply(tunnel_tx, stream_id, SOCKS5_REPLY_SUCCESS).await;
+
+    Some((local_tcp, buf))
+}
+
+/// Read `StreamMessage::Data` chunks into `buf` until `done` returns true.
+async fn read_until(
+    stream_rx: &mut futures::channel::mpsc::UnboundedReceiver<StreamMessage>,
+    buf: &mut Vec<u8>,
+    done: impl Fn(&[u8]) -> bool,
+) -> Option<()> {
+    while !done(buf) {
+        match stream_rx.next().await {
+            Some(StreamMessage::Data(data)) => buf.extend_from_slice(&data),
+            Some(StreamMessage::Close) | None => return None,
+        }
+    }
+    Some(())
+}
+
+fn socks5_request_len(buf: &[u8]) -> Option<usize> {
+    if buf.len() < 4 {
+        return None;
+    }
+    let addr_len = match buf[3] {
+        SOCKS5_ATYP_IPV4 => 4,
+        SOCKS5_ATYP_IPV6 => 16,
+        SOCKS5_ATYP_DOMAIN => {
+            if buf.len() < 5 {
+                return None;
+            }
+            1 + buf[4] as usize
+        }
+        _ => return None,
+    };
+    let total = 4 + addr_len + 2;
+    if buf.len() < total {
+        None
+    } else {
+        Some(total)
+    }
+}
+
+fn parse_socks5_request(buf: &[u8]) -> Option<String> {
+    if buf[0] != SOCKS5_VERSION || buf[1] != SOCKS5_CMD_CONNECT {
+        return None;
+    }
+
+    let (host, rest) = match buf[3] {
+        SOCKS5_ATYP_IPV4 => (
+            std::net::Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]).to_string(),
+            &buf[8..],
+        ),
+        SOCKS5_ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&buf[4..20]);
+            (std::net::Ipv6Addr::from(octets).to_string(), &buf[20..])
+        }
+        SOCKS5_ATYP_DOMAIN => {
+            let len = buf[4] as usize;
+            let domain = std::str::from_utf8(&buf[5..5 + len]).ok()?.to_string();
+            (domain, &buf[5 + len..])
+        }
+        _ => return None,
+    };
+
+    let port = u16::from_be_bytes([rest[0], rest[1]]);
+    Some(format!("{}:{}", host, port))
+}
+
+async fn dial_socks5_target(
+    proxy_addr: Option<std::net::SocketAddr>,
+    target: &str,
+) -> std::io::Result<TcpStream> {
+    match proxy_addr {
+        None => TcpStream::connect(target).await,
+        Some(proxy_addr) => {
+            let mut proxy = TcpStream::connect(proxy_addr).await?;
+            proxy.write_all(&[SOCKS5_VERSION, 1, SOCKS5_METHOD_NO_AUTH]).await?;
+
+            let mut reply = [0u8; 2];
+            proxy.read_exact(&mut reply).await?;
+            if reply[0] != SOCKS5_VERSION || reply[1] != SOCKS5_METHOD_NO_AUTH {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "upstream socks5 proxy rejected our greeting",
+                ));
+            }
+
+            let (host, port_str) = target.rsplit_once(':').ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "malformed socks5 target")
+            })?;
+            let port: u16 = port_str
+                .parse()
+                .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "malformed socks5 target"))?;
+
+            let mut request = vec![SOCKS5_VERSION, SOCKS5_CMD_CONNECT, 0x00, SOCKS5_ATYP_DOMAIN];
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+            request.extend_from_slice(&port.to_be_bytes());
+            proxy.write_all(&request).await?;
+
+            let mut header = [0u8; 4];
+            proxy.read_exact(&mut header).await?;
+            if header[1] != SOCKS5_REPLY_SUCCESS {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("upstream socks5 proxy refused connection: {}", header[1]),
+                ));
+            }
+            let addr_len = match header[3] {
+                SOCKS5_ATYP_IPV4 => 4,
+                SOCKS5_ATYP_IPV6 => 16,
+                SOCKS5_ATYP_DOMAIN => {
+                    let mut len_buf = [0u8; 1];
+                    proxy.read_exact(&mut len_buf).await?;
+                    len_buf[0] as usize
+                }
+                _ => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        "upstream socks5 proxy returned an unsupported address type",
+                    ))
+                }
+            };
+            let mut rest = vec![0u8; addr_len + 2];
+            proxy.read_exact(&mut rest).await?;
+
+            Ok(proxy)
+        }
+    }
+}
+
+async fn send_socks5_reply(tunnel_tx: &mut UnboundedSender<ControlPacket>, stream_id: &StreamId, code: u8) {
+    let reply = vec![
+        SOCKS5_VERSION,
+        code,
+        0x00,
+        SOCKS5_ATYP_IPV4,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+    ];
+    let _ = tunnel_tx.send(ControlPacket::Data(stream_id.clone(), reply)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_len_waits_for_the_full_ipv4_request() {
+        let partial = [SOCKS5_VERSION, SOCKS5_CMD_CONNECT, 0x00, SOCKS5_ATYP_IPV4, 1, 2, 3];
+        assert_eq!(socks5_request_len(&partial), None);
+
+        let full = [SOCKS5_VERSION, SOCKS5_CMD_CONNECT, 0x00, SOCKS5_ATYP_IPV4, 1, 2, 3, 4, 0x00, 0x50];
+        assert_eq!(socks5_request_len(&full), Some(10));
+    }
+
+    #[test]
+    fn request_len_waits_for_the_domain_length_byte() {
+        // only VER CMD RSV ATYP, no length byte yet
+        let partial = [SOCKS5_VERSION, SOCKS5_CMD_CONNECT, 0x00, SOCKS5_ATYP_DOMAIN];
+        assert_eq!(socks5_request_len(&partial), None);
+
+        // length byte present but the domain + port haven't arrived yet
+        let partial = [SOCKS5_VERSION, SOCKS5_CMD_CONNECT, 0x00, SOCKS5_ATYP_DOMAIN, 11];
+        assert_eq!(socks5_request_len(&partial), None);
+    }
+
+    #[test]
+    fn request_len_rejects_unknown_atyp() {
+        let buf = [SOCKS5_VERSION, SOCKS5_CMD_CONNECT, 0x00, 0xFF, 1, 2, 3, 4, 0x00, 0x50];
+        assert_eq!(socks5_request_len(&buf), None);
+    }
+
+    #[test]
+    fn parses_ipv4_connect_request() {
+        let buf = [SOCKS5_VERSION, SOCKS5_CMD_CONNECT, 0x00, SOCKS5_ATYP_IPV4, 93, 184, 216, 34, 0x00, 0x50];
+        assert_eq!(parse_socks5_request(&buf).as_deref(), Some("93.184.216.34:80"));
+    }
+
+    #[test]
+    fn parses_domain_connect_request() {
+        let mut buf = vec![SOCKS5_VERSION, SOCKS5_CMD_CONNECT, 0x00, SOCKS5_ATYP_DOMAIN];
+        let domain = b"example.com";
+        buf.push(domain.len() as u8);
+        buf.extend_from_slice(domain);
+        buf.extend_from_slice(&443u16.to_be_bytes());
+
+        assert_eq!(parse_socks5_request(&buf).as_deref(), Some("example.com:443"));
+    }
+
+    #[test]
+    fn rejects_non_connect_commands() {
+        // CMD 0x02 is BIND, which we don't support
+        let buf = [SOCKS5_VERSION, 0x02, 0x00, SOCKS5_ATYP_IPV4, 1, 2, 3, 4, 0x00, 0x50];
+        assert_eq!(parse_socks5_request(&buf), None);
+    }
+
+    #[tokio::test]
+    async fn dial_direct_fails_for_an_unreachable_target() {
+        // nothing is listening on this port
+        let err = dial_socks5_target(None, "127.0.0.1:1").await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn dial_through_proxy_fails_when_proxy_refuses_the_connect() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+
+            // greeting: accept no-auth
+            let mut greeting = [0u8; 3];
+            socket.read_exact(&mut greeting).await.unwrap();
+            socket.write_all(&[SOCKS5_VERSION, SOCKS5_METHOD_NO_AUTH]).await.unwrap();
+
+            // CONNECT: refuse it
+            let mut header = [0u8; 4];
+            socket.read_exact(&mut header).await.unwrap();
+            let mut rest = vec![0u8; 1 + 11 + 2]; // len byte + "example.com" + port
+            socket.read_exact(&mut rest).await.unwrap();
+            socket
+                .write_all(&[SOCKS5_VERSION, 0x05, 0x00, SOCKS5_ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+        });
+
+        let err = dial_socks5_target(Some(proxy_addr), "example.com:443").await.unwrap_err();
+        assert!(err.to_string().contains("refused connection"));
+    }
+}
@@ -1,31 +1,49 @@
 use futures::{StreamExt, SinkExt};
 use futures::channel::mpsc::{unbounded, UnboundedSender};
 
-use tokio_tungstenite::{WebSocketStream, MaybeTlsStream};
+use tokio_tungstenite::WebSocketStream;
+use tokio_rustls::{client::TlsStream, TlsConnector};
 use tokio::net::TcpStream;
 use tungstenite::protocol::Message;
 
 pub use log::{info, debug, warn, error};
 use human_panic::setup_panic;
 
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::sync::{Arc, RwLock, Mutex};
 use std::env;
 
 mod local;
 mod config;
 mod introspect;
+mod tls;
 
 pub use wormhole::*;
 pub use config::*;
 
 use colour::*;
-use std::time::Duration;
+use rand::Rng;
+use std::time::{Duration, Instant};
 
 pub type ActiveStreams = Arc<RwLock<HashMap<StreamId, UnboundedSender<StreamMessage>>>>;
 
+/// A TCP connection to the local service that's been dialed ahead of time,
+/// sitting idle until a new `StreamId` needs it.
+pub struct PooledConnection {
+    pub stream: TcpStream,
+    pub idle_since: Instant,
+}
+
+pub type LocalPool = Arc<Mutex<VecDeque<PooledConnection>>>;
+
+/// Timestamp of the last message seen on the control websocket, watched by
+/// the heartbeat watchdog in `run_wormhole`.
+pub type LastSeen = Arc<Mutex<Instant>>;
+
 lazy_static::lazy_static! {
     pub static ref ACTIVE_STREAMS:ActiveStreams = Arc::new(RwLock::new(HashMap::new()));
+    pub static ref LOCAL_POOL:LocalPool = Arc::new(Mutex::new(VecDeque::new()));
 }
 
 #[derive(Debug, Clone)]
@@ -46,17 +64,56 @@ async fn main() {
 
     e_green_ln!("Welcome to wormhole!\n{}\n", include_str!("../../wormhole_ascii.txt"));
 
+    introspect::maybe_spawn_dashboard(&config).await;
+
+    if config.local_pool_size > 0 {
+        tokio::spawn(local::maintain_pool(config.clone()));
+    }
+
+    let mut attempt: u32 = 0;
     loop {
         let (restart_tx, mut restart_rx) = unbounded();
+        let connected_at = Instant::now();
         let wormhole = run_wormhole(config.clone(), restart_tx);
         let _  = futures::future::select(Box::pin(wormhole), restart_rx.next()).await;
-        info!("restarting wormhole");
+
+        if connected_at.elapsed() >= config.reconnect_reset_threshold {
+            attempt = 0;
+        }
+
+        if let Some(max_attempts) = config.reconnect_max_attempts {
+            if attempt >= max_attempts {
+                error!("giving up after {} failed reconnect attempts", attempt);
+                return;
+            }
+        }
+
+        let delay = full_jitter_backoff(config.reconnect_base_delay, config.reconnect_max_delay, attempt);
+        warn!("wormhole disconnected, reconnecting in {:?} (attempt {})", delay, attempt + 1);
+        tokio::time::delay_for(delay).await;
+        attempt += 1;
     }
 }
 
+/// Full-jitter exponential backoff: `random_between(0, min(cap, base * 2^attempt))`.
+fn full_jitter_backoff(base: Duration, cap: Duration, attempt: u32) -> Duration {
+    let exp_millis = base
+        .as_millis()
+        .saturating_mul(1u128 << attempt.min(32))
+        .min(cap.as_millis());
+    let jittered = rand::thread_rng().gen_range(0, exp_millis.max(1) as u64);
+    Duration::from_millis(jittered)
+}
+
 /// Setup the tunnel to our control server
 async fn run_wormhole(config: Config, mut restart_tx: UnboundedSender<()>) {
-    let websocket = connect_to_wormhole(&config).await;
+    let websocket = match connect_to_wormhole(&config).await {
+        Ok(websocket) => websocket,
+        Err(e) => {
+            warn!("failed to connect to wormhole server: {}", e);
+            return;
+        }
+    };
 
     // split reading and writing
     let (mut ws_sink, mut ws_stream) = websocket.split();
@@ -64,6 +121,29 @@ async fn run_wormhole(config: Config, mut restart_tx: UnboundedSender<()>) {
     // tunnel channel
     let (mut tunnel_tx, mut tunnel_rx) = unbounded::<ControlPacket>();
 
+    // timestamp of the last message we've heard from the server, used by
+    // the watchdog below to detect a half-open connection
+    let last_seen: LastSeen = Arc::new(Mutex::new(Instant::now()));
+
+    // watchdog: if we haven't heard anything in a while, the tunnel is
+    // probably dead even though the websocket hasn't errored out
+    {
+        let last_seen = last_seen.clone();
+        let mut restart_tx = restart_tx.clone();
+        let missed_ping_timeout = config.missed_ping_timeout;
+        tokio::spawn(async move {
+            loop {
+                tokio::time::delay_for(Duration::from_secs(1)).await;
+                let elapsed = last_seen.lock().unwrap().elapsed();
+                if elapsed > missed_ping_timeout {
+                    warn!("no message from wormhole server in {:?}, assuming the tunnel is dead", elapsed);
+                    let _ = restart_tx.send(()).await;
+                    return
+                }
+            }
+        });
+    }
+
     // continuously write to websocket tunnel
     tokio::spawn(async move {
         loop {
@@ -91,7 +171,9 @@ async fn run_wormhole(config: Config, mut restart_tx: UnboundedSender<()>) {
     loop {
         match ws_stream.next().await {
             Some(Ok(message)) => {
-                if let Err(e) = process_control_flow_message(&config, tunnel_tx.clone(), message.into_data()).await {
+                *last_seen.lock().unwrap() = Instant::now();
+
+                if let Err(e) = process_control_flow_message(&config, tunnel_tx.clone(), message.into_data(), &last_seen).await {
                     error!("Malformed protocol control packet: {:?}", e);
                     return
                 }
@@ -108,15 +190,27 @@ async fn run_wormhole(config: Config, mut restart_tx: UnboundedSender<()>) {
     }
 }
 
-async fn connect_to_wormhole(config: &Config) -> WebSocketStream<MaybeTlsStream<TcpStream>> {
-    let (mut websocket, _) = tokio_tungstenite::connect_async(&config.control_url).await.expect("Failed to connect to wormhole server.");
+async fn connect_to_wormhole(config: &Config) -> Result<WebSocketStream<TlsStream<TcpStream>>, Box<dyn std::error::Error>> {
+    let url = url::Url::parse(&config.control_url)?;
+    let host = url.host_str().ok_or("control_url is missing a host")?.to_string();
+    let port = url.port_or_known_default().unwrap_or(443);
+
+    let tcp = TcpStream::connect((host.as_str(), port)).await?;
+
+    let client_config = tls::build_client_config(config)?;
+    let connector = TlsConnector::from(Arc::new(client_config));
+    let server_name = rustls::ServerName::try_from(host.as_str())
+        .map_err(|e| format!("invalid server name for TLS: {:?}", e))?;
+    let tls_stream = connector.connect(server_name, tcp).await?;
+
+    let (mut websocket, _) = tokio_tungstenite::client_async(&config.control_url, tls_stream).await?;
 
     // send our Client Hello message
     let (client_hello, id) = ClientHello::generate(config.client_id.clone(), &config.secret_key, Some(config.sub_domain.clone()));
     info!("connecting to wormhole as client {}", &id);
 
-    let hello = serde_json::to_vec(&client_hello).unwrap();
-    websocket.send(Message::binary(hello)).await.expect("Failed to send client hello to wormhole server.");
+    let hello = serde_json::to_vec(&client_hello)?;
+    websocket.send(Message::binary(hello)).await?;
 
     // wait for Server hello
     let sub_domain = match websocket.next().await.map(|d| d
@@ -132,35 +226,33 @@ async fn connect_to_wormhole(config: &Config) -> WebSocketStream<MaybeTlsStream<
                 },
                 ServerHello::AuthFailed => {
                     error!("server denied our authentication token.");
-                    panic!("Authentication failed. Check your authentication key.");
+                    return Err("Authentication failed. Check your authentication key.".into());
                 },
                 ServerHello::InvalidSubDomain =>{
-                    panic!("Invalid sub-domain specified");
+                    return Err("Invalid sub-domain specified.".into());
                 }
                 ServerHello::SubDomainInUse => {
                     error!("sub-domain already in use");
-                    panic!("Cannot use this sub-domain, it's already taken.")
+                    return Err("Cannot use this sub-domain, it's already taken.".into());
                 }
             }
         }
         Some(Ok(Err(e))) => {
-            error!("invalid server hello: {:?}", e);
-            panic!("connection failed.");
+            return Err(format!("invalid server hello: {:?}", e).into());
         },
         Some(Err(e)) => {
-            error!("websocket error: {:?}", e);
-            panic!("connection failed.");
+            return Err(format!("websocket error: {:?}", e).into());
         }
         None => {
-            panic!("Empty reply from server. Unknown failure to connect to server.")
+            return Err("Empty reply from server. Unknown failure to connect to server.".into());
         }
     };
 
     eprintln!("Wormhole activated on: {}", config.activation_url(&sub_domain));
-    websocket
+    Ok(websocket)
 }
 
-async fn process_control_flow_message(config: &Config, mut tunnel_tx: UnboundedSender<ControlPacket>, payload: Vec<u8>) -> Result<(), Box<dyn std::error::Error>> {
+async fn process_control_flow_message(config: &Config, mut tunnel_tx: UnboundedSender<ControlPacket>, payload: Vec<u8>, last_seen: &LastSeen) -> Result<(), Box<dyn std::error::Error>> {
     let control_packet = ControlPacket::deserialize(&payload)?;
 
     match control_packet {
@@ -169,6 +261,7 @@ async fn process_control_flow_message(config: &Config, mut tunnel_tx: UnboundedS
         },
         ControlPacket::Ping => {
             log::info!("got ping");
+            *last_seen.lock().unwrap() = Instant::now();
 
             let mut tx = tunnel_tx.clone();
             tokio::spawn(async move {
@@ -197,20 +290,37 @@ async fn process_control_flow_message(config: &Config, mut tunnel_tx: UnboundedS
         ControlPacket::Data(stream_id, data) => {
             info!("stream[{:?}] -> new data: {:?}", stream_id.to_string(), data.len());
 
-            if !ACTIVE_STREAMS.read().unwrap().contains_key(&stream_id) {
-                local::setup_new_stream(&config.local_port, tunnel_tx.clone(), stream_id.clone()).await;
+            if config.introspect_addr.is_some() {
+                introspect::capture(&stream_id, &data);
             }
 
-            // find the right stream
-            let active_stream = ACTIVE_STREAMS.read().unwrap().get(&stream_id).cloned();
+            let is_new_stream = !ACTIVE_STREAMS.read().unwrap().contains_key(&stream_id);
 
-            // forward data to it
-            if let Some(mut tx) = active_stream {
-                tx.send(StreamMessage::Data(data)).await?;
-                info!("forwarded to local tcp ({})", stream_id.to_string());
+            if is_new_stream {
+                // `data` is this stream's first payload -- setup_new_stream
+                // seeds the stream's channel with it directly, since a
+                // SOCKS5 handshake needs to consume it right away and we
+                // (the read loop) can't send it ourselves until setup
+                // returns
+                local::setup_new_stream(config, tunnel_tx.clone(), stream_id.clone(), data).await;
             } else {
-                error!("got data but no stream to send it to.");
-                let _ = tunnel_tx.send(ControlPacket::Refused(stream_id)).await?;
+                let active_stream = ACTIVE_STREAMS.read().unwrap().get(&stream_id).cloned();
+
+                if let Some(mut tx) = active_stream {
+                    if tx.send(StreamMessage::Data(data)).await.is_err() {
+                        // the stream's receiver is gone (e.g. a SOCKS5
+                        // handshake that failed) -- that's a dead stream,
+                        // not a reason to tear down the whole tunnel
+                        warn!("stream[{:?}] -> local channel closed, refusing", stream_id.to_string());
+                        ACTIVE_STREAMS.write().unwrap().remove(&stream_id);
+                        let _ = tunnel_tx.send(ControlPacket::Refused(stream_id)).await?;
+                    } else {
+                        info!("forwarded to local tcp ({})", stream_id.to_string());
+                    }
+                } else {
+                    error!("got data but no stream to send it to.");
+                    let _ = tunnel_tx.send(ControlPacket::Refused(stream_id)).await?;
+                }
             }
         },
     };
@@ -0,0 +1,86 @@
+use super::*;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc as StdArc;
+
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore};
+
+/// A `ServerCertVerifier` that accepts any certificate, for
+/// `--danger-accept-invalid-certs`. Only ever installed when the user opts
+/// in explicitly.
+struct NoCertVerification;
+
+impl rustls::client::ServerCertVerifier for NoCertVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &rustls::client::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+/// Build the rustls `ClientConfig` used for the control connection,
+/// honouring `Config`'s custom CA, invalid-cert override, and client
+/// certificate for mTLS.
+pub fn build_client_config(config: &Config) -> Result<ClientConfig, Box<dyn std::error::Error>> {
+    let mut roots = RootCertStore::empty();
+    match &config.root_ca_path {
+        Some(path) => {
+            let mut reader = BufReader::new(File::open(path)?);
+            for cert in rustls_pemfile::certs(&mut reader)? {
+                roots.add(&Certificate(cert))?;
+            }
+        }
+        None => {
+            roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+                rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            }));
+        }
+    }
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+
+    let mut client_config = match &config.client_cert {
+        Some((cert_path, key_path)) => {
+            let certs = load_certs(cert_path)?;
+            let key = load_private_key(key_path)?;
+            builder.with_single_cert(certs, key)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    if config.danger_accept_invalid_certs {
+        warn!("TLS certificate validation is disabled, this connection is not authenticated");
+        client_config
+            .dangerous()
+            .set_certificate_verifier(StdArc::new(NoCertVerification));
+    }
+
+    Ok(client_config)
+}
+
+fn load_certs(path: &std::path::Path) -> Result<Vec<Certificate>, Box<dyn std::error::Error>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    Ok(rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect())
+}
+
+fn load_private_key(path: &std::path::Path) -> Result<PrivateKey, Box<dyn std::error::Error>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let keys = rustls_pemfile::pkcs8_private_keys(&mut reader)?;
+    let key = keys.into_iter().next().ok_or("no private key found in client-key file")?;
+    Ok(PrivateKey(key))
+}
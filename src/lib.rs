@@ -0,0 +1,88 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// How often the client pings the server to keep the tunnel alive.
+pub const PING_INTERVAL: u64 = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ClientId(pub ulid::Ulid);
+
+impl ClientId {
+    pub fn generate() -> Self {
+        ClientId(ulid::Ulid::new())
+    }
+}
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct StreamId(pub ulid::Ulid);
+
+impl StreamId {
+    pub fn generate() -> Self {
+        StreamId(ulid::Ulid::new())
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ControlPacket {
+    Init(StreamId),
+    Data(StreamId, Vec<u8>),
+    Refused(StreamId),
+    End(StreamId),
+    Ping,
+}
+
+impl ControlPacket {
+    pub fn serialize(&self) -> Vec<u8> {
+        serde_cbor::to_vec(self).expect("failed to serialize control packet")
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Self, serde_cbor::Error> {
+        serde_cbor::from_slice(data)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientHello {
+    pub id: ClientId,
+    pub sub_domain: Option<String>,
+    pub secret_key: Option<String>,
+}
+
+impl ClientHello {
+    /// Build the handshake we send as the very first websocket message.
+    pub fn generate(
+        client_id: Option<ClientId>,
+        secret_key: &Option<String>,
+        sub_domain: Option<String>,
+    ) -> (Self, ClientId) {
+        let id = client_id.unwrap_or_else(ClientId::generate);
+        (
+            ClientHello {
+                id: id.clone(),
+                sub_domain,
+                secret_key: secret_key.clone(),
+            },
+            id,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ServerHello {
+    Success { sub_domain: String },
+    AuthFailed,
+    InvalidSubDomain,
+    SubDomainInUse,
+}